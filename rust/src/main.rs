@@ -17,12 +17,240 @@
 
 use abm;
 use num_cpus;
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use clap::parser::ValueSource;
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
 use threadpool::ThreadPool;
 
-/// This struct handles the command line arguments.
-#[derive(Parser, Debug, Clone)]
+/// Global allocator wrapper that counts allocations and bytes allocated so
+/// that hidden allocations can be compared across implementations.
+#[cfg(feature = "count_allocations")]
+mod allocations {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    pub struct CountingAllocator;
+
+    pub static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    pub static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout,
+                          new_size: usize) -> *mut u8 {
+            // Only the growth is new memory; the old block's bytes were
+            // already counted when it was allocated.
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            BYTES.fetch_add(new_size.saturating_sub(layout.size()),
+                            Ordering::Relaxed);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAllocator = CountingAllocator;
+
+    /// Prints the allocation totals to stderr so the CSV output is untouched.
+    pub fn report() {
+        eprintln!("allocations: {}, bytes: {}",
+                  ALLOCATIONS.load(Ordering::Relaxed),
+                  BYTES.load(Ordering::Relaxed));
+    }
+}
+
+/// Length of one iteration. Rates are always given per day and are converted
+/// to per-iteration rates before a simulation starts.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TimeUnit {
+    Day,
+    Week,
+    Month,
+}
+
+impl TimeUnit {
+    /// Number of days in one iteration (a month is taken to be 30 days).
+    fn days(self) -> i32 {
+        match self {
+            TimeUnit::Day => 1,
+            TimeUnit::Week => 7,
+            TimeUnit::Month => 30,
+        }
+    }
+
+    /// Converts a per-day probability into the probability of the event
+    /// happening at least once in the unit, 1 - (1 - p)^days. This assumes
+    /// the daily draws are independent and ignores agents changing state
+    /// more than once within a unit, so coarser units are an approximation.
+    /// Daily probabilities are returned unchanged so that the default run
+    /// passes exactly the given values to the simulation.
+    fn probability(self, p: f64) -> f64 {
+        match self.days() {
+            1 => p,
+            days => 1.0 - (1.0 - p).powi(days),
+        }
+    }
+
+    /// Compounds a per-day growth rate over the unit. A daily rate is
+    /// returned unchanged.
+    fn growth(self, g: f64) -> f64 {
+        match self.days() {
+            1 => g,
+            days => (1.0 + g).powi(days) - 1.0,
+        }
+    }
+}
+
+/// Parameters that can be swept with --sweep.
+const SWEEP_FIELDS: [&str; 8] = [
+    "agents",
+    "encounters",
+    "growth",
+    "death_prob_susceptible",
+    "death_prob_infectious",
+    "recovery_prob",
+    "vaccination_prob",
+    "regression_prob",
+];
+
+/// A parameter and the values it takes in a sweep.
+#[derive(Debug, Clone, PartialEq)]
+struct Sweep {
+    field: String,
+    values: Vec<f64>,
+    /// Decimal places used when printing a value.
+    decimals: usize,
+}
+
+impl Sweep {
+    /// Returns the line printed before the output for one value of the
+    /// sweep. The value is rounded to the decimal places of the start and
+    /// step given by the user, so floating point noise does not show.
+    fn marker(&self, value: f64) -> String {
+        format!("# sweep {}={:.*}", self.field, self.decimals, value)
+    }
+}
+
+/// Largest number of values accepted by --sweep.
+const MAX_SWEEP_POINTS: f64 = 1000.0;
+
+/// Returns the number of decimal places written in a number, taking any
+/// exponent into account, e.g. 2 for "0.25" and 5 for "1e-5".
+fn decimal_places(arg: &str) -> i32 {
+    let (mantissa, exponent) = match arg.find(['e', 'E']) {
+        Some(i) => (&arg[..i], arg[i + 1..].parse::<i32>().unwrap_or(0)),
+        None => (arg, 0),
+    };
+    let fraction = mantissa.split_once('.').map_or(0, |(_, f)| f.len() as i32);
+    (fraction - exponent).max(0)
+}
+
+/// Parses a sweep given as <field>:<start>:<stop>:<step>. Values run from
+/// start up to and including stop.
+fn parse_sweep(arg: &str) -> Result<Sweep, String> {
+    let parts: Vec<&str> = arg.split(':').collect();
+    if parts.len() != 4 {
+        return Err(String::from("expected <field>:<start>:<stop>:<step>"));
+    }
+    let field = parts[0];
+    if !SWEEP_FIELDS.contains(&field) {
+        return Err(format!("cannot sweep {}, expected one of {}",
+                           field, SWEEP_FIELDS.join(", ")));
+    }
+    let mut numbers = [0.0; 3];
+    for (number, part) in numbers.iter_mut().zip(&parts[1..]) {
+        *number = part.parse::<f64>()
+            .map_err(|e| format!("invalid number {}: {}", part, e))?;
+    }
+    let [start, stop, step] = numbers;
+    if !numbers.iter().all(|n| n.is_finite()) || step <= 0.0 || stop < start {
+        return Err(String::from("step must be positive and stop must not be \
+                                 less than start"));
+    }
+    if (field == "agents" || field == "encounters")
+        && !numbers.iter().all(|n| *n >= 0.0 && n.fract() == 0.0) {
+        return Err(format!("{} takes whole numbers, so start, stop and step \
+                            must be non-negative integers", field));
+    }
+    // The small tolerance keeps stop in the sweep despite rounding error.
+    let steps = ((stop - start) / step + 1e-9).floor();
+    if !steps.is_finite() || steps + 1.0 > MAX_SWEEP_POINTS {
+        return Err(format!("sweep has more than {} values", MAX_SWEEP_POINTS));
+    }
+    Ok(Sweep {
+        field: String::from(field),
+        values: (0..=steps as usize).map(|k| start + k as f64 * step).collect(),
+        decimals: decimal_places(parts[1]).max(decimal_places(parts[3])) as usize,
+    })
+}
+
+/// Largest thread count accepted by --threads.
+const MAX_THREADS: usize = 1024;
+
+/// Checks that a thread count is not absurdly large.
+fn check_threads(value: usize) -> Result<usize, String> {
+    if value > MAX_THREADS {
+        return Err(format!("{} exceeds the maximum of {}", value, MAX_THREADS));
+    }
+    Ok(value)
+}
+
+/// Parses a thread count, rejecting absurdly large values.
+fn parse_threads(arg: &str) -> Result<usize, String> {
+    check_threads(arg.parse::<usize>()
+        .map_err(|e| format!("invalid number {}: {}", arg, e))?)
+}
+
+/// Checks that a number is between 0 and 1 inclusive.
+fn check_fraction(value: f64) -> Result<f64, String> {
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("{} is not between 0 and 1", value));
+    }
+    Ok(value)
+}
+
+/// Parses a number between 0 and 1 inclusive.
+fn parse_fraction(arg: &str) -> Result<f64, String> {
+    check_fraction(arg.parse::<f64>()
+        .map_err(|e| format!("invalid number {}: {}", arg, e))?)
+}
+
+/// Reads an infection method from a config file, either as its number or as
+/// "both", "one" or "two".
+fn deserialize_method<'de, D: Deserializer<'de>>(deserializer: D)
+                                                 -> Result<u8, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Method {
+        Number(u8),
+        Name(String),
+    }
+    match Method::deserialize(deserializer)? {
+        Method::Number(n) => Ok(n),
+        Method::Name(name) => match name.to_lowercase().as_str() {
+            "both" => Ok(0),
+            "one" => Ok(1),
+            "two" => Ok(2),
+            _ => Err(D::Error::custom(format!("unknown infection method {}",
+                                              name))),
+        },
+    }
+}
+
+/// This struct handles the command line arguments. The simulation parameters
+/// can also be read from a TOML config file with --config.
+#[derive(Parser, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[command(version, about, long_about = None, rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
 struct Parameters {
     /// Number of simulations
     #[arg(short, long, default_value_t = 20)]
@@ -32,7 +260,7 @@ struct Parameters {
     #[arg(long, default_value_t = 0)]
     pub identity: usize,
 
-    /// Number of iterations in a simulation
+    /// Number of iterations (in units of time_unit) in a simulation
     #[arg(short, long, default_value_t = 365 * 4)]
     pub iterations: i32,
 
@@ -74,57 +302,538 @@ struct Parameters {
 
     /// Infection method to use (0 = BOTH, 1 = ONE, 2 = TWO)
     #[arg(long, default_value_t = 0)]
+    #[serde(deserialize_with = "deserialize_method")]
     pub infection_method: u8,
 
+    /// Fraction of simulations using method ONE when infection_method is
+    /// BOTH. Identity i uses ONE if ceil((i + 1) * both_split) >
+    /// ceil(i * both_split), otherwise TWO, so any run of consecutive
+    /// identities has about this fraction using ONE. 0.5 and unset both give
+    /// ONE for even identities and TWO for odd identities.
+    #[arg(long, value_parser = parse_fraction)]
+    pub both_split: Option<f64>,
+
+    /// Length of an iteration; per-day rates and encounters are scaled to it
+    #[arg(long, value_enum, default_value_t = TimeUnit::Day)]
+    pub time_unit: TimeUnit,
+
     /// Iteration frequency to write out agents (0 = never)
     #[arg(long, default_value_t = 0)]
     pub output_agents: i32,
 
-    /// Agent output file name
+    /// Agent output file name ({} is replaced by the simulation identity)
     #[arg(long, default_value_t = String::from("agents.csv"))]
-    pub agent_filename: String
+    pub agent_filename: String,
+
+    /// Number of consecutive simulations run by each thread pool job
+    /// (0 = one job per simulation)
+    #[arg(long, default_value_t = 0)]
+    pub chunk_size: usize,
+
+    /// Number of threads in the pool (0 = all cores)
+    #[arg(long, default_value_t = 0, value_parser = parse_threads)]
+    pub threads: usize,
+
+    /// Run the ensemble once for each value of a parameter, given as
+    /// <field>:<start>:<stop>:<step>. The output for each value is preceded
+    /// by a line "# sweep <field>=<value>"
+    #[arg(long, value_parser = parse_sweep)]
+    #[serde(skip)]
+    pub sweep: Option<Sweep>,
+
+    /// Show a progress bar on stderr as simulations complete
+    #[cfg(feature = "progress")]
+    #[arg(long, default_value_t = false)]
+    #[serde(skip)]
+    pub progress: bool,
+
+    /// Check the parameters, print the resolved values and exit
+    #[arg(long, default_value_t = false)]
+    #[serde(skip)]
+    pub validate: bool,
+
+    /// TOML file of parameters; options given on the command line take
+    /// precedence over the file
+    #[arg(long)]
+    #[serde(skip)]
+    pub config: Option<String>
 }
 
-/// Runs one simulation. Called within the thread pool so has to be thread
-/// safe.
-fn one_simulation(parameters: Parameters) {
-    let abm_parameters = abm::Parameters {
+/// Loads the parameters in a TOML config file. Any parameter given on the
+/// command line takes precedence over the file, and parameters missing from
+/// the file take their command line defaults. The options that control how
+/// the program runs (--sweep, --progress, --validate and --config) are only
+/// read from the command line.
+fn load_config(path: &str, cli: &Parameters, matches: &ArgMatches)
+               -> Result<Parameters, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let mut table: toml::Table = text.parse()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    let defaults = toml::Table::try_from(cli).map_err(|e| e.to_string())?;
+    for (key, value) in defaults {
+        let given = matches.value_source(&key) == Some(ValueSource::CommandLine);
+        if given || !table.contains_key(&key) {
+            table.insert(key, value);
+        }
+    }
+    let parameters: Parameters = table.try_into()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    check_threads(parameters.threads)?;
+    if let Some(split) = parameters.both_split {
+        check_fraction(split)?;
+    }
+    Ok(Parameters {
+        sweep: cli.sweep.clone(),
+        #[cfg(feature = "progress")]
+        progress: cli.progress,
+        validate: cli.validate,
+        config: cli.config.clone(),
+        ..parameters
+    })
+}
+
+/// Returns whether the simulation with the given identity uses method ONE in
+/// BOTH mode. The rule depends only on the identity, so a single simulation
+/// run with --identity uses the same method as in a full ensemble.
+fn uses_method_one(identity: usize, both_split: Option<f64>) -> bool {
+    match both_split {
+        Some(split) => ((identity + 1) as f64 * split).ceil()
+            > (identity as f64 * split).ceil(),
+        None => identity.is_multiple_of(2),
+    }
+}
+
+/// Describes which infection method the simulations will use.
+fn describe_method(parameters: &Parameters) -> String {
+    let name = |one| if one { "ONE" } else { "TWO" };
+    match parameters.infection_method {
+        1 => String::from("ONE"),
+        2 => String::from("TWO"),
+        _ if parameters.simulations <= 1 => {
+            format!("BOTH (identity {} uses {})", parameters.identity,
+                    name(uses_method_one(parameters.identity,
+                                         parameters.both_split)))
+        },
+        _ => {
+            let ones = (0..parameters.simulations)
+                .filter(|&i| uses_method_one(i, parameters.both_split))
+                .count();
+            format!("BOTH ({} of {} simulations use ONE, the rest use TWO)",
+                    ones, parameters.simulations)
+        },
+    }
+}
+
+/// Converts the command line arguments into the parameters of a single
+/// simulation.
+fn abm_parameters(parameters: &Parameters) -> abm::Parameters {
+    let unit = parameters.time_unit;
+    abm::Parameters {
         agents: parameters.agents,
         iterations: parameters.iterations,
         infections: parameters.infections,
-        encounters: parameters.encounters,
-        growth: parameters.growth,
-        death_prob_susceptible: parameters.death_prob_susceptible,
-        death_prob_infectious: parameters.death_prob_infectious,
-        recovery_prob: parameters.recovery_prob,
-        vaccination_prob: parameters.vaccination_prob,
-        regression_prob: parameters.regression_prob,
-	infection_method: match parameters.infection_method {
-	    1 => abm::InfectionMethod::ONE,
-	    2 => abm::InfectionMethod::TWO,
-	    _ => abm::InfectionMethod::BOTH,
-	},
+        encounters: parameters.encounters * unit.days() as usize,
+        growth: unit.growth(parameters.growth),
+        death_prob_susceptible: unit.probability(parameters.death_prob_susceptible),
+        death_prob_infectious: unit.probability(parameters.death_prob_infectious),
+        recovery_prob: unit.probability(parameters.recovery_prob),
+        vaccination_prob: unit.probability(parameters.vaccination_prob),
+        regression_prob: unit.probability(parameters.regression_prob),
+        infection_method: match (parameters.infection_method,
+                                 parameters.both_split) {
+            (1, _) => abm::InfectionMethod::ONE,
+            (2, _) => abm::InfectionMethod::TWO,
+            (_, Some(_)) => {
+                if uses_method_one(parameters.identity, parameters.both_split) {
+                    abm::InfectionMethod::ONE
+                } else {
+                    abm::InfectionMethod::TWO
+                }
+            },
+            _ => abm::InfectionMethod::BOTH,
+        },
         output_agents: parameters.output_agents,
-        agent_filename: parameters.agent_filename.clone()
-    };
+        agent_filename: agent_filename(parameters)
+    }
+}
+
+/// Returns the agent output file name of a simulation, replacing any {} in
+/// the name with the simulation identity so that simulations running in
+/// parallel do not write to the same file.
+fn agent_filename(parameters: &Parameters) -> String {
+    parameters.agent_filename.replace("{}", &parameters.identity.to_string())
+}
+
+/// Checks that the command line arguments describe a runnable simulation.
+/// Returns a description of the first problem found.
+fn validate(parameters: &Parameters) -> Result<(), String> {
+    let probabilities = [
+        ("death_prob_susceptible", parameters.death_prob_susceptible),
+        ("death_prob_infectious", parameters.death_prob_infectious),
+        ("recovery_prob", parameters.recovery_prob),
+        ("vaccination_prob", parameters.vaccination_prob),
+        ("regression_prob", parameters.regression_prob),
+    ];
+    for (name, value) in probabilities {
+        if !(0.0..=1.0).contains(&value) {
+            return Err(format!("{} must be between 0 and 1, got {}",
+                               name, value));
+        }
+    }
+    if !parameters.growth.is_finite() || parameters.growth < 0.0 {
+        return Err(format!("growth must be a non-negative number, got {}",
+                           parameters.growth));
+    }
+    if parameters.infection_method > 2 {
+        return Err(format!("infection_method must be 0, 1 or 2, got {}",
+                           parameters.infection_method));
+    }
+    if parameters.iterations < 0 {
+        return Err(format!("iterations must not be negative, got {}",
+                           parameters.iterations));
+    }
+    if parameters.output_agents < 0 {
+        return Err(format!("output_agents must not be negative, got {}",
+                           parameters.output_agents));
+    }
+    Ok(())
+}
+
+/// Prints the per-iteration values a simulation would run with and an
+/// estimate of the memory used by the initial agents.
+fn print_resolved(parameters: &Parameters) {
+    let p = abm_parameters(parameters);
+    println!("simulations = {}", parameters.simulations);
+    println!("agents = {}", p.agents);
+    println!("iterations = {}", p.iterations);
+    println!("infections = {}", p.infections);
+    println!("encounters = {}", p.encounters);
+    println!("growth = {}", p.growth);
+    println!("death_prob_susceptible = {}", p.death_prob_susceptible);
+    println!("death_prob_infectious = {}", p.death_prob_infectious);
+    println!("recovery_prob = {}", p.recovery_prob);
+    println!("vaccination_prob = {}", p.vaccination_prob);
+    println!("regression_prob = {}", p.regression_prob);
+    println!("infection_method = {}", describe_method(parameters));
+    match parameters.both_split {
+        Some(split) => println!("both_split = {}", split),
+        None => println!("both_split = none"),
+    }
+    println!("time_unit = {}", parameters.time_unit.to_possible_value()
+             .map_or(String::new(), |v| String::from(v.get_name())));
+    println!("threads = {}", thread_count(parameters));
+    println!("chunk_size = {}", parameters.chunk_size);
+    println!("output_agents = {}", p.output_agents);
+    println!("agent_filename = {}", p.agent_filename);
+    println!("estimated agent memory per simulation = {} bytes",
+             p.agents * std::mem::size_of::<abm::Agent>());
+}
+
+/// Sets the parameter named by a sweep. parse_sweep has already checked
+/// that values for integer parameters are whole numbers.
+fn set_field(parameters: &mut Parameters, field: &str, value: f64) {
+    match field {
+        "agents" => parameters.agents = value.round() as usize,
+        "encounters" => parameters.encounters = value.round() as usize,
+        "growth" => parameters.growth = value,
+        "death_prob_susceptible" => parameters.death_prob_susceptible = value,
+        "death_prob_infectious" => parameters.death_prob_infectious = value,
+        "recovery_prob" => parameters.recovery_prob = value,
+        "vaccination_prob" => parameters.vaccination_prob = value,
+        "regression_prob" => parameters.regression_prob = value,
+        _ => unreachable!("parse_sweep only accepts SWEEP_FIELDS"),
+    }
+}
+
+/// Returns the parameters for each point of the sweep, paired with the swept
+/// value, or just the given parameters if there is no sweep.
+fn sweep_points(parameters: &Parameters) -> Vec<(Option<f64>, Parameters)> {
+    match &parameters.sweep {
+        None => vec![(None, parameters.clone())],
+        Some(sweep) => sweep.values.iter().map(|&value| {
+            let mut p = parameters.clone();
+            set_field(&mut p, &sweep.field, value);
+            (Some(value), p)
+        }).collect(),
+    }
+}
+
+/// Progress bar advanced as each simulation in the thread pool completes.
+/// Does nothing unless built with the progress feature, run with --progress
+/// and stderr is a terminal.
+#[derive(Clone)]
+struct Progress {
+    #[cfg(feature = "progress")]
+    bar: Option<indicatif::ProgressBar>,
+}
+
+impl Progress {
+    fn new(parameters: &Parameters) -> Progress {
+        #[cfg(feature = "progress")]
+        {
+            use std::io::IsTerminal;
+            let show = parameters.progress && std::io::stderr().is_terminal();
+            Progress {
+                bar: show.then(|| {
+                    indicatif::ProgressBar::new(parameters.simulations as u64)
+                }),
+            }
+        }
+        #[cfg(not(feature = "progress"))]
+        {
+            let _ = parameters;
+            Progress {}
+        }
+    }
+
+    fn inc(&self) {
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+
+    fn finish(&self) {
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.finish();
+        }
+    }
+}
+
+/// Runs one simulation. Called within the thread pool so has to be thread
+/// safe.
+fn one_simulation(parameters: &Parameters) {
+    let abm_parameters = abm_parameters(parameters);
     let mut s = abm::Simulation::new(parameters.identity, &abm_parameters);
     s.simulate();
 }
 
-/// Processes parameters, sets up thread pool and invokes the execution of the
-/// simulations.
- fn main() {
-    let parameters =  Parameters::parse();
+/// Returns the number of threads the simulations run on. There is no point in
+/// having more threads than simulations.
+fn thread_count(parameters: &Parameters) -> usize {
+    let threads = match parameters.threads {
+        0 => num_cpus::get(),
+        n => n,
+    };
+    threads.min(parameters.simulations).max(1)
+}
+
+/// Sets up thread pool and invokes the execution of the simulations.
+fn run_simulations(parameters: &Parameters) {
     if parameters.simulations <= 1 {
          one_simulation(parameters);
     } else {
-        let threads = num_cpus::get();
-        let pool = ThreadPool::new(threads);
-        for i in 0..parameters.simulations {
-            let mut p = parameters.clone();
-            p.identity = i;
-            pool.execute(move|| {one_simulation(p)});
+        let pool = ThreadPool::new(thread_count(parameters));
+        let progress = Progress::new(parameters);
+        if parameters.chunk_size == 0 {
+            for i in 0..parameters.simulations {
+                let mut p = parameters.clone();
+                p.identity = i;
+                let progress = progress.clone();
+                pool.execute(move|| {
+                    one_simulation(&p);
+                    progress.inc();
+                });
+            }
+        } else {
+            // Each job runs a contiguous range of identities sequentially,
+            // sharing one copy of the parameters.
+            for start in (0..parameters.simulations).step_by(parameters.chunk_size) {
+                let end = (start + parameters.chunk_size).min(parameters.simulations);
+                let mut p = parameters.clone();
+                let progress = progress.clone();
+                pool.execute(move|| {
+                    for i in start..end {
+                        p.identity = i;
+                        one_simulation(&p);
+                        progress.inc();
+                    }
+                });
+            }
         }
         pool.join();
+        progress.finish();
+    }
+}
+
+/// Processes parameters and runs the simulations, once for each point of the
+/// sweep if there is one.
+ fn main() {
+    let matches = Parameters::command().get_matches();
+    let mut parameters = Parameters::from_arg_matches(&matches)
+        .unwrap_or_else(|e| e.exit());
+    if let Some(path) = &parameters.config {
+        parameters = load_config(path, &parameters, &matches)
+            .unwrap_or_else(|message| {
+                eprintln!("Invalid config: {}", message);
+                std::process::exit(1);
+            });
+    }
+    if parameters.both_split.is_some() && parameters.infection_method != 0 {
+        eprintln!("Warning: both_split is ignored unless infection_method is \
+                   0 (BOTH)");
+    }
+    if parameters.threads > parameters.simulations.max(1) {
+        eprintln!("Warning: threads ({}) exceeds simulations ({}), so only {} \
+                   will be used", parameters.threads, parameters.simulations,
+                  thread_count(&parameters));
+    }
+    let mut points = sweep_points(&parameters);
+    for (_, p) in &mut points {
+        // abm::Simulation::new slices the first `infections` agents, so more
+        // infections than agents would panic.
+        if p.infections > p.agents {
+            eprintln!("Warning: infections ({}) exceeds agents ({}), so all \
+                       agents will be infectious", p.infections, p.agents);
+            p.infections = p.agents;
+        }
+    }
+    if parameters.validate {
+        for (_, p) in &points {
+            if let Err(message) = validate(p) {
+                eprintln!("Invalid parameters: {}", message);
+                std::process::exit(1);
+            }
+        }
+        for (value, p) in &points {
+            if let (Some(sweep), Some(value)) = (&parameters.sweep, value) {
+                println!("{}", sweep.marker(*value));
+            }
+            print_resolved(p);
+        }
+        std::process::exit(0);
+    }
+    for (value, p) in points {
+        // Report lines come from abm, so each point of the sweep is marked
+        // by a line before its output.
+        if let (Some(sweep), Some(value)) = (&parameters.sweep, value) {
+            println!("{}", sweep.marker(value));
+        }
+        run_simulations(&p);
+    }
+    #[cfg(feature = "count_allocations")]
+    allocations::report();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_split_half_matches_default_rule() {
+        for identity in 0..100 {
+            assert_eq!(uses_method_one(identity, Some(0.5)),
+                       uses_method_one(identity, None));
+        }
+    }
+
+    #[test]
+    fn both_split_fraction_of_identities() {
+        let ones = (0..100).filter(|&i| uses_method_one(i, Some(0.3))).count();
+        assert_eq!(ones, 30);
+        assert!((0..100).all(|i| uses_method_one(i, Some(1.0))));
+        assert!((0..100).all(|i| !uses_method_one(i, Some(0.0))));
+    }
+
+    #[test]
+    fn agent_filename_placeholder() {
+        let mut parameters = Parameters::parse_from(
+            ["simulation", "--agent_filename", "agents_{}.csv"]);
+        parameters.identity = 3;
+        assert_eq!(agent_filename(&parameters), "agents_3.csv");
+        parameters.identity = 4;
+        assert_eq!(agent_filename(&parameters), "agents_4.csv");
+    }
+
+    #[test]
+    fn parameters_toml_round_trip() {
+        let parameters = Parameters::parse_from(
+            ["simulation", "--agents", "500", "--both_split", "0.25",
+             "--time_unit", "week", "--infection_method", "2"]);
+        let text = toml::to_string(&parameters).unwrap();
+        let loaded: Parameters = toml::from_str(&text).unwrap();
+        assert_eq!(loaded, parameters);
+    }
+
+    /// Writes a config file to the temporary directory and loads it with the
+    /// given command line.
+    fn load(name: &str, text: &str, args: &[&str]) -> Result<Parameters, String> {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, text).unwrap();
+        let path = path.to_str().unwrap();
+        let mut argv = vec!["simulation", "--config", path];
+        argv.extend_from_slice(args);
+        let matches = Parameters::command().get_matches_from(argv);
+        let cli = Parameters::from_arg_matches(&matches).unwrap();
+        load_config(path, &cli, &matches)
+    }
+
+    #[test]
+    fn config_command_line_takes_precedence() {
+        let parameters = load("simulation_precedence.toml",
+                              "agents = 123\nrecovery_prob = 0.5\n\
+                               infection_method = \"two\"\n",
+                              &["--agents", "7"]).unwrap();
+        assert_eq!(parameters.agents, 7);
+        assert_eq!(parameters.recovery_prob, 0.5);
+        assert_eq!(parameters.infection_method, 2);
+        assert_eq!(parameters.encounters, 100);
+    }
+
+    #[test]
+    fn config_rejects_bad_values() {
+        assert!(load("simulation_unknown.toml", "agent = 5\n", &[]).is_err());
+        assert!(load("simulation_method.toml", "infection_method = \"three\"\n",
+                     &[]).is_err());
+        assert!(load("simulation_split.toml", "both_split = 2.0\n", &[]).is_err());
+    }
+
+    #[test]
+    fn two_point_sweep() {
+        let parameters = Parameters::parse_from(
+            ["simulation", "--sweep", "recovery_prob:0.01:0.02:0.01"]);
+        let points = sweep_points(&parameters);
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].0, Some(0.01));
+        assert_eq!(points[0].1.recovery_prob, 0.01);
+        assert!((points[1].1.recovery_prob - 0.02).abs() < 1e-12);
+        assert_eq!(points[1].1.agents, parameters.agents);
+    }
+
+    #[test]
+    fn sweep_rejects_unknown_field() {
+        assert!(parse_sweep("foo:1:2:1").is_err());
+    }
+
+    #[test]
+    fn sweep_rejects_fractional_agents() {
+        assert!(parse_sweep("agents:100:200:0.5").is_err());
+        assert!(parse_sweep("agents:-100:200:100").is_err());
+    }
+
+    #[test]
+    fn sweep_size_limit() {
+        assert_eq!(parse_sweep("agents:1:1000:1").unwrap().values.len(), 1000);
+        assert!(parse_sweep("agents:1:1001:1").is_err());
+        assert!(parse_sweep("agents:0:1e15:1").is_err());
+    }
+
+    #[test]
+    fn sweep_exponent_arguments() {
+        let sweep = parse_sweep("growth:1e-5:3e-5:1e-5").unwrap();
+        assert_eq!(sweep.values.len(), 3);
+        assert_eq!(sweep.marker(sweep.values[2]), "# sweep growth=0.00003");
+        let sweep = parse_sweep("growth:1e-17:3e-17:1e-17").unwrap();
+        assert_eq!(sweep.values[0], 1e-17);
+        assert!(sweep.values.iter().all(|&v| v > 0.0));
+        assert_eq!(sweep.marker(sweep.values[0]), "# sweep growth=0.00000000000000001");
+    }
+
+    #[test]
+    fn sweep_marker_hides_float_noise() {
+        let sweep = parse_sweep("recovery_prob:0.1:0.3:0.1").unwrap();
+        assert_eq!(sweep.marker(sweep.values[2]), "# sweep recovery_prob=0.3");
     }
 }